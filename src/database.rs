@@ -2,13 +2,16 @@ use crate::{Options, PgDump, PgRestore, Psql};
 
 use log::{debug, info, trace};
 use semver::{BuildMetadata, Prerelease, Version};
+use sha2::{Digest, Sha256};
 use std::{
     ffi::OsStr,
+    fs,
     path::{Path, PathBuf},
     process::{Output, Stdio},
     result,
+    time::Instant,
 };
-use tokio::process::Command;
+use tokio::{io::AsyncWriteExt, process::Command};
 use url::Url;
 
 const API_SCHEMA_DIRECTORY: &str = "api";
@@ -24,6 +27,69 @@ const DEFAULT_VERSION: Version = Version {
 
 pub type Result = result::Result<(), String>;
 
+// Selects and orders migration files between two versions. Runs ascending
+// when to_version >= from_version, descending otherwise (as for rollback).
+// from_version is included (unless it equals to_version and including_to is
+// not set); to_version only when including_to is set.
+#[derive(Debug)]
+struct Migrator {
+    from_version: Version,
+    to_version: Version,
+    including_to: bool,
+}
+
+impl Migrator {
+    fn new(
+        from_version: Version,
+        to_version: Version,
+        including_to: bool,
+    ) -> Self {
+        Self {
+            from_version,
+            to_version,
+            including_to,
+        }
+    }
+
+    fn plan(
+        &self,
+        migrations: &[(Version, PathBuf)],
+    ) -> Vec<(Version, PathBuf)> {
+        let ascending = self.to_version >= self.from_version;
+
+        let mut selected: Vec<_> = migrations
+            .iter()
+            .filter(|(version, _)| {
+                if ascending {
+                    let from_ok = *version >= self.from_version;
+                    let to_ok = match self.including_to {
+                        true => *version <= self.to_version,
+                        false => *version < self.to_version,
+                    };
+
+                    from_ok && to_ok
+                } else {
+                    let from_ok = *version <= self.from_version;
+                    let to_ok = match self.including_to {
+                        true => *version >= self.to_version,
+                        false => *version > self.to_version,
+                    };
+
+                    from_ok && to_ok
+                }
+            })
+            .cloned()
+            .collect();
+
+        selected.sort_by(|a, b| match ascending {
+            true => a.0.cmp(&b.0),
+            false => b.0.cmp(&a.0),
+        });
+
+        selected
+    }
+}
+
 #[derive(Debug)]
 pub struct Database {
     version: Version,
@@ -33,6 +99,7 @@ pub struct Database {
     pg_dump: PgDump,
     pg_restore: PgRestore,
     sql_directory: PathBuf,
+    atomic: bool,
 }
 
 impl Database {
@@ -57,9 +124,14 @@ impl Database {
             pg_dump: options.pg_dump.clone(),
             pg_restore: options.pg_restore.clone(),
             sql_directory: options.sql_directory.to_owned(),
+            atomic: options.atomic,
         })
     }
 
+    pub fn supported_major_version(&self) -> u64 {
+        self.version.major
+    }
+
     pub async fn check_schema_version(&self) -> Result {
         match self.schema_version().await? {
             Some(version) if version == self.version => {
@@ -67,6 +139,8 @@ impl Database {
                 return Ok(());
             }
             Some(version) => {
+                self.check_major_version(&version)?;
+
                 info!(
                     "Data schema out of date (v{version}): \
                     starting migration..."
@@ -117,12 +191,50 @@ impl Database {
 
     pub async fn migrate(&self) -> Result {
         self.drop_api_schema().await?;
-        self.migrate_data().await?;
+        self.migrate_data(&self.version.clone(), false).await?;
         self.update().await?;
 
         Ok(())
     }
 
+    pub async fn migrate_to(&self, target: Version) -> Result {
+        if target > self.version {
+            return Err(format!(
+                "migration target ({target}) is greater than app version \
+                ({}): cannot migrate beyond the compiled schema version",
+                self.version
+            ));
+        }
+
+        self.migrate_data(&target, true).await
+    }
+
+    pub async fn plan(
+        &self,
+    ) -> result::Result<Vec<(Version, PathBuf)>, String> {
+        let schema_version = self.schema_version().await?;
+
+        if let Some(version) = &schema_version {
+            self.check_major_version(version)?;
+        }
+
+        let schema_version = schema_version.unwrap_or(DEFAULT_VERSION);
+
+        if schema_version > self.version {
+            return Err(format!(
+                "schema version ({schema_version}) is greater than \
+                app version ({}): downgrades are only done by rollback",
+                self.version
+            ));
+        }
+
+        let migrations = self.discover_migrations()?;
+        let migrator =
+            Migrator::new(schema_version, self.version.clone(), false);
+
+        Ok(migrator.plan(&migrations))
+    }
+
     pub async fn reset(&self) -> Result {
         self.drop_api_schema().await?;
         self.drop_data_schema().await?;
@@ -145,6 +257,96 @@ impl Database {
         Ok(())
     }
 
+    pub async fn rollback(&self, target: Version) -> Result {
+        let schema_version = self.schema_version().await?.ok_or_else(|| {
+            String::from("cannot rollback: data schema is not initialized")
+        })?;
+
+        self.check_major_version(&schema_version)?;
+
+        if target >= schema_version {
+            return Err(format!(
+                "rollback target ({target}) must be less than the current \
+                schema version ({schema_version})"
+            ));
+        }
+
+        let migration_directory = self.sql_directory.join(MIGRATION_DIRECTORY);
+        let all_migrations = self.discover_migrations()?;
+        let migrator = Migrator::new(schema_version, target.clone(), false);
+        let migrations = migrator.plan(&all_migrations);
+
+        if migrations.is_empty() {
+            debug!("No migrations to roll back");
+            return Ok(());
+        }
+
+        let mut down_scripts = Vec::with_capacity(migrations.len());
+
+        for (version, _) in &migrations {
+            let mut path = migration_directory.join(format!("{version}.down"));
+            path.set_extension("sql");
+
+            if !path.is_file() {
+                return Err(format!(
+                    "cannot rollback past v{version}: missing down script '{}'",
+                    path.display()
+                ));
+            }
+
+            down_scripts.push(path);
+        }
+
+        debug!(
+            "Rolling back {} migration{}",
+            migrations.len(),
+            match migrations.len() {
+                1 => "",
+                _ => "s",
+            }
+        );
+
+        let set_search_path = format!("SET search_path TO {DATA_SCHEMA}");
+        let set_search_path = OsStr::new(&set_search_path);
+
+        let mut iter = migrations
+            .iter()
+            .map(|(version, _)| version)
+            .zip(down_scripts.iter())
+            .peekable();
+
+        while let Some((version, path)) = iter.next() {
+            info!("Rolling back v{version}");
+
+            self.psql([
+                OsStr::new("--command"),
+                set_search_path,
+                OsStr::new("--single-transaction"),
+                OsStr::new("--file"),
+                path.as_os_str(),
+            ])
+            .await
+            .map_err(|err| {
+                format!(
+                    "failed to apply down script '{}': {err}",
+                    path.display()
+                )
+            })?;
+
+            // The down script for `version` undoes the migration to
+            // `version`, landing the schema on the preceding migration in
+            // the walk (or on `target` once there is none left).
+            let preceding = match iter.peek() {
+                Some((version, _)) => (*version).clone(),
+                None => target.clone(),
+            };
+
+            self.set_schema_version(&preceding).await?;
+        }
+
+        Ok(())
+    }
+
     async fn exec(
         &self,
         mut command: Command,
@@ -233,25 +435,156 @@ impl Database {
         self.drop_schema(DATA_SCHEMA).await
     }
 
-    async fn migrate_data(&self) -> Result {
-        let schema_version =
-            self.schema_version().await?.unwrap_or(DEFAULT_VERSION);
+    fn checksum(path: &Path) -> result::Result<String, String> {
+        let bytes = fs::read(path).map_err(|err| {
+            format!("failed to read migration file '{}': {err}", path.display())
+        })?;
 
-        if schema_version == self.version {
-            debug!(
-                "Schema version and app version are equal: nothing to migrate"
-            );
+        Ok(format!("{:x}", Sha256::digest(&bytes)))
+    }
+
+    async fn ensure_migrations_table(&self) -> Result {
+        let exists = self
+            .query(
+                "SELECT exists(\
+                    SELECT * FROM pg_tables \
+                    WHERE schemaname = 'data' \
+                    AND tablename = 'applied_migrations'\
+                )",
+            )
+            .await?;
+
+        let table_exists = match exists.as_str() {
+            "t" => true,
+            "f" => false,
+            _ => {
+                return Err(format!(
+                    "unexpected psql output when checking \
+                    if applied_migrations table exists: {exists}"
+                ))
+            }
+        };
+
+        if !table_exists {
+            // Carry the version recorded by the old single-function scheme
+            // forward so upgrading databases aren't mistaken for fresh ones.
+            let previous = self.schema_version().await?;
+
+            self.query(
+                "CREATE TABLE data.applied_migrations (\
+                    version text PRIMARY KEY, \
+                    checksum text, \
+                    applied_at timestamptz NOT NULL DEFAULT now(), \
+                    duration_ms bigint NOT NULL DEFAULT 0\
+                )",
+            )
+            .await
+            .map_err(|err| {
+                format!("failed to create applied_migrations table: {err}")
+            })?;
+
+            if let Some(version) = previous {
+                self.record_migration(&version, None, 0).await?;
+            }
+        }
+
+        // version is stored as text, so ordering must split it into its
+        // numeric components: plain MAX(version) is lexicographic and would
+        // rank '1.9.0' above '1.10.0'.
+        self.query(
+            "CREATE OR REPLACE FUNCTION data.schema_version() \
+            RETURNS text AS $$ \
+                SELECT version FROM data.applied_migrations \
+                ORDER BY \
+                    split_part(version, '.', 1)::int DESC, \
+                    split_part(version, '.', 2)::int DESC, \
+                    split_part(version, '.', 3)::int DESC \
+                LIMIT 1 \
+            $$ LANGUAGE sql STABLE",
+        )
+        .await
+        .map_err(|err| format!("failed to define schema_version(): {err}"))?;
+
+        Ok(())
+    }
+
+    fn upsert_migration_sql(
+        version: &Version,
+        checksum: Option<&str>,
+        duration_ms: i64,
+    ) -> String {
+        let checksum = match checksum {
+            Some(checksum) => format!("'{checksum}'"),
+            None => "NULL".to_string(),
+        };
+
+        // A NULL checksum means this call is only repointing the current
+        // version (rollback, migrate_to landing short of the newest file),
+        // not recording a freshly-applied migration: keep whatever checksum
+        // and duration were legitimately recorded for that version already.
+        format!(
+            "INSERT INTO data.applied_migrations \
+                (version, checksum, duration_ms) \
+            VALUES ('{version}', {checksum}, {duration_ms}) \
+            ON CONFLICT (version) DO UPDATE SET \
+                checksum = COALESCE( \
+                    EXCLUDED.checksum, applied_migrations.checksum \
+                ), \
+                applied_at = now(), \
+                duration_ms = CASE WHEN EXCLUDED.checksum IS NULL \
+                    THEN applied_migrations.duration_ms \
+                    ELSE EXCLUDED.duration_ms \
+                END"
+        )
+    }
+
+    async fn record_migration(
+        &self,
+        version: &Version,
+        checksum: Option<&str>,
+        duration_ms: i64,
+    ) -> Result {
+        self.query(&Self::upsert_migration_sql(
+            version,
+            checksum,
+            duration_ms,
+        ))
+        .await
+        .map_err(|err| {
+            format!("failed to record applied migration {version}: {err}")
+        })?;
+
+        Ok(())
+    }
+
+    async fn verify_checksum(&self, version: &Version, path: &Path) -> Result {
+        let recorded = self
+            .query(&format!(
+                "SELECT checksum FROM data.applied_migrations \
+                WHERE version = '{version}'"
+            ))
+            .await?;
+
+        if recorded.is_empty() {
             return Ok(());
         }
 
-        if schema_version > self.version {
+        let checksum = Self::checksum(path)?;
+
+        if checksum != recorded {
             return Err(format!(
-                "schema version ({schema_version}) is greater than \
-                app version ({}): downgrades are not supported",
-                self.version
+                "migration file '{}' has changed since it was applied: \
+                recorded checksum {recorded}, on-disk checksum {checksum}",
+                path.display()
             ));
         }
 
+        Ok(())
+    }
+
+    fn discover_migrations(
+        &self,
+    ) -> result::Result<Vec<(Version, PathBuf)>, String> {
         let migration_directory = self.sql_directory.join(MIGRATION_DIRECTORY);
 
         if !migration_directory.exists() {
@@ -259,7 +592,7 @@ impl Database {
                 "No migrations to run: directory '{}' does not exist",
                 migration_directory.display()
             );
-            return Ok(());
+            return Ok(Vec::new());
         }
 
         if !migration_directory.is_dir() {
@@ -300,57 +633,118 @@ impl Database {
                     )
                 })?;
 
-            let file_version = Version::parse(file_version).map_err(|err| {
+            // Down scripts ("<version>.down.sql") live alongside their "up"
+            // counterparts but are only ever consulted by rollback().
+            if file_version.ends_with(".down") {
+                debug!("Skipping '{}': down script", path.display());
+                continue;
+            }
+
+            let version = Version::parse(file_version).map_err(|err| {
                 format!(
                     "file name contains invalid version '{}': {err}",
                     path.display()
                 )
             })?;
 
-            if schema_version > file_version {
-                debug!(
-                    "Skipping '{}': schema version is greater",
-                    path.display()
-                );
-                continue;
-            }
+            debug!("Discovered migration: {}", path.display());
+            migrations.push((version, path));
+        }
 
-            if file_version >= self.version {
-                debug!(
-                    "Skipping '{}': greater than or equal to target",
-                    path.display()
-                );
-                continue;
-            }
+        migrations.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(migrations)
+    }
 
-            debug!("Adding migration: {}", path.display());
-            migrations.push((file_version, path));
+    fn check_major_version(&self, schema_version: &Version) -> Result {
+        if schema_version.major != self.version.major {
+            return Err(format!(
+                "this database was created by an incompatible major \
+                version (v{schema_version}): this build only supports \
+                schema major version {}",
+                self.version.major
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn migrate_data(
+        &self,
+        target: &Version,
+        including_to: bool,
+    ) -> Result {
+        let schema_version = self.schema_version().await?;
+
+        if let Some(version) = &schema_version {
+            self.check_major_version(version)?;
         }
 
+        let schema_version = schema_version.unwrap_or(DEFAULT_VERSION);
+
+        if schema_version == *target {
+            debug!(
+                "Schema version and target version are equal: \
+                nothing to migrate"
+            );
+            return Ok(());
+        }
+
+        if schema_version > *target {
+            return Err(format!(
+                "schema version ({schema_version}) is greater than \
+                target version ({target}): downgrades are only done by rollback"
+            ));
+        }
+
+        self.ensure_migrations_table().await?;
+
+        let all_migrations = self.discover_migrations()?;
+
+        for (version, path) in &all_migrations {
+            if *version < schema_version {
+                self.verify_checksum(version, path).await?;
+            }
+        }
+
+        let migrator =
+            Migrator::new(schema_version, target.clone(), including_to);
+        let migrations = migrator.plan(&all_migrations);
+
         if migrations.is_empty() {
             debug!("No migrations to run");
             return Ok(());
-        } else {
-            debug!(
-                "Applying {} migration{}",
-                migrations.len(),
-                match migrations.len() {
-                    1 => "",
-                    _ => "s",
-                }
-            );
         }
 
-        migrations.sort_by(|a, b| a.0.cmp(&b.0));
+        debug!(
+            "Applying {} migration{}",
+            migrations.len(),
+            match migrations.len() {
+                1 => "",
+                _ => "s",
+            }
+        );
+
+        if self.atomic {
+            self.apply_migrations_atomic(&migrations, target).await
+        } else {
+            self.apply_migrations_stepwise(&migrations, target).await
+        }
+    }
 
+    async fn apply_migrations_stepwise(
+        &self,
+        migrations: &[(Version, PathBuf)],
+        target: &Version,
+    ) -> Result {
         let set_search_path = format!("SET search_path TO {DATA_SCHEMA}");
         let set_search_path = OsStr::new(&set_search_path);
 
-        let mut iter = migrations.iter().peekable();
-
-        while let Some((version, path)) = iter.next() {
+        for (version, path) in migrations {
             info!("Migrating from v{version}");
 
+            let started = Instant::now();
+
             self.psql([
                 OsStr::new("--command"),
                 set_search_path,
@@ -366,15 +760,109 @@ impl Database {
                 )
             })?;
 
-            let next = match iter.peek() {
-                Some((version, _)) => version,
-                None => &self.version,
-            };
+            let duration_ms = started.elapsed().as_millis() as i64;
+            let checksum = Self::checksum(path)?;
 
-            self.set_schema_version(next).await?;
+            self.record_migration(version, Some(&checksum), duration_ms)
+                .await?;
         }
 
-        Ok(())
+        self.set_schema_version(target).await
+    }
+
+    async fn apply_migrations_atomic(
+        &self,
+        migrations: &[(Version, PathBuf)],
+        target: &Version,
+    ) -> Result {
+        let mut script = format!("SET search_path TO {DATA_SCHEMA};\n");
+
+        for (version, path) in migrations {
+            let contents = fs::read_to_string(path).map_err(|err| {
+                format!(
+                    "failed to read migration script '{}': {err}",
+                    path.display()
+                )
+            })?;
+
+            let checksum = Self::checksum(path)?;
+
+            script.push_str(&format!("\\echo MIGRATING {version}\n"));
+            script.push_str(&contents);
+            script.push_str(&format!(
+                "\n{};\n",
+                Self::upsert_migration_sql(version, Some(&checksum), 0)
+            ));
+        }
+
+        script.push_str(&format!(
+            "{};\n",
+            Self::upsert_migration_sql(target, None, 0)
+        ));
+
+        self.psql_atomic(script).await
+    }
+
+    async fn psql_atomic(&self, script: String) -> Result {
+        let mut command = self.psql.command();
+
+        command
+            .args([
+                "--quiet",
+                "--no-psqlrc",
+                "--single-transaction",
+                "--set=ON_ERROR_STOP=1",
+                "--file",
+                "-",
+            ])
+            .arg("--dbname")
+            .arg(self.connection.as_str())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = command.spawn().map_err(|err| {
+            format!("failed to spawn child process 'psql': {err}")
+        })?;
+
+        let mut stdin = child.stdin.take().expect("stdin was piped");
+        let write = async move {
+            stdin.write_all(script.as_bytes()).await?;
+            stdin.shutdown().await
+        };
+
+        let (write_result, output) =
+            tokio::join!(write, child.wait_with_output());
+
+        write_result.map_err(|err| {
+            format!("failed to write migration script to psql: {err}")
+        })?;
+
+        let Output {
+            status,
+            stdout,
+            stderr,
+        } = output.map_err(|err| format!("failed to run psql: {err}"))?;
+
+        if status.success() {
+            return Ok(());
+        }
+
+        let failing_version = String::from_utf8_lossy(&stdout)
+            .lines()
+            .filter_map(|line| line.strip_prefix("MIGRATING "))
+            .last()
+            .map(String::from);
+
+        let error = String::from_utf8_lossy(&stderr);
+        let error = error.trim();
+
+        Err(match failing_version {
+            Some(version) => format!(
+                "migration run failed while applying v{version}: {error}"
+            ),
+            None => format!("migration run failed: {error}"),
+        })
     }
 
     async fn psql<I, S>(&self, args: I) -> result::Result<String, String>
@@ -427,6 +915,11 @@ impl Database {
         }
 
         let version = self.query("SELECT data.schema_version()").await?;
+
+        if version.is_empty() {
+            return Ok(None);
+        }
+
         let version = Version::parse(&version).map_err(|err| {
             format!("invalid data schema version '{version}': {err}")
         })?;
@@ -435,19 +928,20 @@ impl Database {
     }
 
     async fn set_schema_version(&self, version: &Version) -> Result {
+        self.ensure_migrations_table().await?;
+        self.record_migration(version, None, 0).await?;
+
+        // schema_version() reports the highest recorded version: drop the
+        // rows above `version` so that it can also move down (rollback).
         self.query(&format!(
-            "CREATE OR REPLACE FUNCTION data.schema_version() \
-            RETURNS text AS $$ \
-            BEGIN \
-                RETURN '{version}'; \
-            END; $$ \
-            IMMUTABLE \
-            LANGUAGE plpgsql"
+            "DELETE FROM data.applied_migrations WHERE (\
+                split_part(version, '.', 1)::int, \
+                split_part(version, '.', 2)::int, \
+                split_part(version, '.', 3)::int\
+            ) > ({}, {}, {})",
+            version.major, version.minor, version.patch
         ))
-        .await
-        .map_err(|err| {
-            format!("failed to set schema version to {version}: {err}")
-        })?;
+        .await?;
 
         Ok(())
     }
@@ -465,3 +959,91 @@ impl Database {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Migrator;
+    use semver::Version;
+    use std::path::PathBuf;
+
+    fn migrations(versions: &[&str]) -> Vec<(Version, PathBuf)> {
+        versions
+            .iter()
+            .map(|version| {
+                let version = Version::parse(version).unwrap();
+                let path = PathBuf::from(format!("{version}.sql"));
+                (version, path)
+            })
+            .collect()
+    }
+
+    fn plan(migrations: &[(Version, PathBuf)]) -> Vec<String> {
+        migrations.iter().map(|(version, _)| version.to_string()).collect()
+    }
+
+    fn version(version: &str) -> Version {
+        Version::parse(version).unwrap()
+    }
+
+    #[test]
+    fn ascending_excludes_to_version_by_default() {
+        let migrations = migrations(&["1.0.0", "1.1.0", "1.2.0"]);
+        let migrator = Migrator::new(version("1.0.0"), version("1.2.0"), false);
+
+        assert_eq!(
+            plan(&migrator.plan(&migrations)),
+            vec!["1.0.0", "1.1.0"]
+        );
+    }
+
+    #[test]
+    fn ascending_includes_to_version_when_requested() {
+        let migrations = migrations(&["1.0.0", "1.1.0", "1.2.0"]);
+        let migrator = Migrator::new(version("1.0.0"), version("1.2.0"), true);
+
+        assert_eq!(
+            plan(&migrator.plan(&migrations)),
+            vec!["1.0.0", "1.1.0", "1.2.0"]
+        );
+    }
+
+    #[test]
+    fn descending_excludes_to_version_by_default() {
+        let migrations = migrations(&["1.0.0", "1.1.0", "1.2.0"]);
+        let migrator = Migrator::new(version("1.2.0"), version("1.0.0"), false);
+
+        assert_eq!(plan(&migrator.plan(&migrations)), vec!["1.2.0", "1.1.0"]);
+    }
+
+    #[test]
+    fn descending_includes_to_version_when_requested() {
+        let migrations = migrations(&["1.0.0", "1.1.0", "1.2.0"]);
+        let migrator = Migrator::new(version("1.1.0"), version("1.0.0"), true);
+
+        assert_eq!(plan(&migrator.plan(&migrations)), vec!["1.1.0", "1.0.0"]);
+    }
+
+    #[test]
+    fn empty_range_when_no_migrations_fall_between_versions() {
+        let migrations = migrations(&["1.0.0", "2.0.0"]);
+        let migrator = Migrator::new(version("1.1.0"), version("1.2.0"), true);
+
+        assert!(migrator.plan(&migrations).is_empty());
+    }
+
+    #[test]
+    fn equal_from_and_to_version_is_empty_without_including_to() {
+        let migrations = migrations(&["1.0.0"]);
+        let migrator = Migrator::new(version("1.0.0"), version("1.0.0"), false);
+
+        assert!(migrator.plan(&migrations).is_empty());
+    }
+
+    #[test]
+    fn equal_from_and_to_version_includes_it_when_requested() {
+        let migrations = migrations(&["1.0.0"]);
+        let migrator = Migrator::new(version("1.0.0"), version("1.0.0"), true);
+
+        assert_eq!(plan(&migrator.plan(&migrations)), vec!["1.0.0"]);
+    }
+}
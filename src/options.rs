@@ -80,4 +80,29 @@ pub struct Options<'a> {
     pub pg_dump: &'a PgDump,
     pub pg_restore: &'a PgRestore,
     pub sql_directory: &'a Path,
+    pub atomic: bool,
+}
+
+impl<'a> Options<'a> {
+    pub fn new(
+        connection: &'a ConnectionParameters,
+        psql: &'a Psql,
+        pg_dump: &'a PgDump,
+        pg_restore: &'a PgRestore,
+        sql_directory: &'a Path,
+    ) -> Self {
+        Self {
+            connection,
+            psql,
+            pg_dump,
+            pg_restore,
+            sql_directory,
+            atomic: true,
+        }
+    }
+
+    pub fn atomic(mut self, atomic: bool) -> Self {
+        self.atomic = atomic;
+        self
+    }
 }